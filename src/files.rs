@@ -2,28 +2,130 @@ use std::collections::{HashMap, HashSet};
 use std::ffi::OsString;
 use std::fs::{DirEntry};
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, SystemTimeError};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 
+/// Bytes and files actually copied while handling a (sub)tree. Handlers return this
+/// per file so the parallel walker can aggregate totals without shared mutable counters.
+#[derive(Default)]
+pub struct CopyStats {
+   pub bytes: u64,
+   pub files: u64,
+}
+
+/// Snapshot of traversal progress, emitted periodically so users can see throughput on
+/// large backups. There is no reliable total up front (the tree is discovered as it is
+/// walked), so progress is reported as a running count rather than a misleading fraction.
+pub struct ProgressData {
+   pub entries_checked: u64,
+   pub bytes_copied: u64,
+}
+
+/// A content digest of a file. BLAKE3 produces a 32-byte hash; storing the raw
+/// bytes keeps `FileMeta` cheap to hash and compare and easy to serialize later.
+pub type Digest = [u8; 32];
+
+/// File name of the manifest written into each backup directory.
+pub const MANIFEST_NAME: &str = "manifest.zst";
+
+/// How symbolic links encountered in the source are handled.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum SymlinkMode {
+   /// Follow the link and back up whatever it points at.
+   Follow,
+   /// Recreate the link itself in the backup without copying its target.
+   Preserve,
+   /// Ignore symbolic links entirely.
+   Skip,
+}
+
+/// Classification for entries that are neither a regular file nor a directory.
+pub enum SpecialKind {
+   /// A symbolic link.
+   Symlink,
+   /// A FIFO, socket, or block/character device node.
+   Other,
+}
+
+/// A modification time kept at full precision (whole seconds plus nanoseconds) so edits
+/// smaller than a second are not lost to truncation.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Timestamp {
+   pub secs: i64,
+   pub nanos: u32,
+}
+
+impl Timestamp {
+   pub fn from_system_time(time: SystemTime) -> Timestamp {
+      match time.duration_since(SystemTime::UNIX_EPOCH) {
+         Ok(dur) => Timestamp { secs: dur.as_secs() as i64, nanos: dur.subsec_nanos() },
+         // Times before the epoch: floor to the whole second below so that `secs` plus
+         // `nanos` still equals the time.
+         Err(err) => {
+            let dur = err.duration();
+            let (secs, nanos) = (dur.as_secs() as i64, dur.subsec_nanos());
+            if nanos == 0 {
+               Timestamp { secs: -secs, nanos: 0 }
+            } else {
+               Timestamp { secs: -secs - 1, nanos: 1_000_000_000 - nanos }
+            }
+         }
+      }
+   }
+
+   // Whether two times fall in the same whole second, which is the resolution some
+   // filesystems store and the basis of the "second-ambiguous" safety rule.
+   pub fn same_second(&self, other: &Timestamp) -> bool {
+      self.secs == other.secs
+   }
+
+   fn abs_diff_secs(&self, other: &Timestamp) -> u64 {
+      (self.secs - other.secs).unsigned_abs()
+   }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct FileMeta {
    pub len: u64,
-   pub modified: SystemTime,
+   pub modified: Timestamp,
+   pub hash: Option<Digest>,
+   // Set when the file's mtime fell in the same second as the backup that recorded it, so a
+   // sub-second write could have been missed. Such a file is always re-checked next run.
+   pub ambiguous: bool,
 }
 
-pub trait FileHandler {
-   fn file(&mut self, file: &std::fs::DirEntry, meta: &std::fs::Metadata) -> std::io::Result<()>;
-   fn dir(&mut self, dir: &std::fs::DirEntry, meta: &std::fs::Metadata) -> std::io::Result<()>;
+// Handlers run concurrently on the worker pool, so `file`/`dir` take `&self` and keep any
+// mutable state behind interior mutability. Each `file` call reports what it copied.
+pub trait FileHandler: Sync {
+   fn file(&self, file: &std::fs::DirEntry, meta: &std::fs::Metadata) -> std::io::Result<CopyStats>;
+   fn dir(&self, dir: &std::fs::DirEntry, meta: &std::fs::Metadata) -> std::io::Result<()>;
+   fn special(&self, entry: &std::fs::DirEntry, meta: &std::fs::Metadata, kind: SpecialKind) -> std::io::Result<()>;
 }
 
 pub struct CollectorFileHandler {
-   pub files: HashMap<PathBuf, FileMeta>,
+   pub files: Mutex<HashMap<PathBuf, FileMeta>>,
+   // Start time of the backup being recorded, used to flag second-ambiguous files. `None`
+   // when walking an older backup whose start time is unknown.
+   pub backup_started: Option<Timestamp>,
+   // Whether to record a content hash for each file. Only set when the caller is hashing,
+   // so the rebuilt `prev_files` can be compared by digest without re-reading every file.
+   pub hash: bool,
 }
 
 impl FileHandler for CollectorFileHandler{
-   fn file(&mut self, file: &std::fs::DirEntry, meta: &std::fs::Metadata) -> std::io::Result<()> {
-      self.files.insert(file.path(), FileMeta { len: meta.len(), modified: meta.modified().unwrap(), });
+   fn file(&self, file: &std::fs::DirEntry, meta: &std::fs::Metadata) -> std::io::Result<CopyStats> {
+      let modified = Timestamp::from_system_time(meta.modified().unwrap());
+      let ambiguous = self.backup_started.is_some_and(|started| started.same_second(&modified));
+      let hash = if self.hash { Some(hash_file(&file.path())?) } else { None };
+      self.files.lock().unwrap().insert(file.path(), FileMeta { len: meta.len(), modified, hash, ambiguous, });
+      Ok(CopyStats::default())
+   }
+   fn dir(&self, _dir: &std::fs::DirEntry, _meta: &std::fs::Metadata) -> std::io::Result<()> {
       Ok(())
    }
-   fn dir(&mut self, _dir: &std::fs::DirEntry, _meta: &std::fs::Metadata) -> std::io::Result<()> {
+   fn special(&self, _entry: &std::fs::DirEntry, _meta: &std::fs::Metadata, _kind: SpecialKind) -> std::io::Result<()> {
+      // The manifest only tracks regular files; special entries carry no length/hash to record.
       Ok(())
    }
 }
@@ -32,15 +134,23 @@ pub struct LinkOrCopyFileHandler<'a>{
 	pub prev_files: &'a HashMap<PathBuf, FileMeta>,
 	pub src_base_dir: &'a Path,
 	pub dest_dir: &'a Path,
+	pub dest_base_dir: &'a Path,
 	pub prev_dir: &'a Path,
 	pub min_diff_secs: u64,
-   pub bytes_copied: u64,
-   pub files_copied: u64,
+	pub backup_started: Timestamp,
+	pub hash: bool,
+	// Maps a content digest to the first destination written with that content this run. Shared
+	// across every source directory so dedup spans the whole backup, not just one source tree.
+	pub digests: &'a Mutex<HashMap<Digest, PathBuf>>,
+	// Metadata of every file written this run (copied, linked or deduplicated), collected as we
+	// go and keyed by path relative to the backup root, so the manifest can be serialized from it
+	// without a second walk of the finished backup.
+	pub collected: &'a Mutex<HashMap<PathBuf, FileMeta>>,
 	pub verbose: bool,
 }
 
 impl<'a> FileHandler for LinkOrCopyFileHandler<'a>{
-   fn file(&mut self, file: &std::fs::DirEntry, meta: &std::fs::Metadata) -> std::io::Result<()> {
+   fn file(&self, file: &std::fs::DirEntry, meta: &std::fs::Metadata) -> std::io::Result<CopyStats> {
       // Now, for each of the directories we have in the source, we need to check recursively all of the files etc.
 		// And there's outcomes...
 		// Same length/modified, make hardlink
@@ -50,28 +160,54 @@ impl<'a> FileHandler for LinkOrCopyFileHandler<'a>{
       let src_path = file.path();
 		let src_file = src_path.strip_prefix(self.src_base_dir).unwrap(); // TODO don't panic here
 		let mut dest_path = PathBuf::from(self.dest_dir);
-		dest_path.push(&src_file);
+		dest_path.push(src_file);
 		let mut prev_path = PathBuf::from(self.prev_dir);
-		prev_path.push(&src_file);
+		prev_path.push(src_file);
       let src_meta = meta;
 
+		// When hashing is requested we digest the source up front: the digest is used both
+		// to confirm a same-length file really is unchanged and to deduplicate against files
+		// already written in this backup.
+		let src_digest = if self.hash { Some(hash_file(&src_path)?) } else { None };
+
+		// Collected once and recorded into the manifest regardless of copy/link/dedup, so the
+		// next run reads it instead of re-walking this backup.
+		let src_ts = Timestamp::from_system_time(src_meta.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+		record_meta(self.collected, self.dest_base_dir, &dest_path, FileMeta {
+			len: src_meta.len(),
+			modified: src_ts,
+			hash: src_digest,
+			ambiguous: src_ts.same_second(&self.backup_started),
+		});
+
 		let copy;
 
 		if let Some(prev_meta) = self.prev_files.get(&prev_path) {
 			if src_meta.len() == prev_meta.len {
-				// lengths are the same so compare the modification times
-				match diff_secs(&src_meta.modified().unwrap_or(SystemTime::UNIX_EPOCH), &prev_meta.modified) {
-					Ok(secs) => {
-						// If the modification time in seconds differs by at least 2 seconds, then assume it has changed and needs to be copied
-						copy = secs >= self.min_diff_secs;
-					}
-					Err(err) => {
-						// Can't tell, so be conservative and assume it's changed
-						println!(
-							"\x1b[mCannot compare filetimes ({}), assuming file has changed",
-							&err
-						);
+				if let Some(src_digest) = src_digest {
+					// Lengths match and the caller opted into hashing, so compare the content of
+					// the source and the previous backup entry. Prefer the digest the previous
+					// manifest already stored and only re-read the old file when it lacks one.
+					let prev_digest = match prev_meta.hash {
+						Some(prev_digest) => prev_digest,
+						None => hash_file(&prev_path)?,
+					};
+					copy = src_digest != prev_digest;
+				} else {
+					// Lengths match; compare modification times at full precision.
+					if prev_meta.ambiguous {
+						// The previous record was second-ambiguous, so its time can't be trusted.
 						copy = true;
+					} else if src_ts.same_second(&self.backup_started) {
+						// A write could still be in flight within this whole second; don't trust
+						// the source time and copy to be safe (Mercurial's second-ambiguous rule).
+						copy = true;
+					} else if self.min_diff_secs == 0 {
+						// Exact comparison (the default): any difference means the file changed.
+						copy = src_ts != prev_meta.modified;
+					} else {
+						// Tolerate clock skew up to the configured number of whole seconds.
+						copy = src_ts.abs_diff_secs(&prev_meta.modified) >= self.min_diff_secs;
 					}
 				}
 			} else {
@@ -84,112 +220,467 @@ impl<'a> FileHandler for LinkOrCopyFileHandler<'a>{
 		}
 
 		if copy {
+			// Cross-file deduplication: if an identical file has already been written in this
+			// backup, hard-link to it instead of copying the same bytes again. The check and the
+			// reservation happen under one lock so two workers hashing the same new content don't
+			// both copy: the first creates the destination inode, any later identical file links
+			// to it.
+			if let Some(digest) = src_digest {
+				let existing = {
+					let mut map = self.digests.lock().unwrap();
+					match map.get(&digest).cloned() {
+						Some(existing) => Some(existing),
+						None => {
+							// Reserve this digest by creating the destination now, while holding the
+							// lock, so a concurrent identical file has an inode to link against; the
+							// copy below truncates and fills that same inode.
+							std::fs::File::create(&dest_path)?;
+							map.insert(digest, dest_path.clone());
+							None
+						}
+					}
+				};
+				if let Some(existing) = existing {
+					return match std::fs::hard_link(&existing, &dest_path) {
+						Ok(_) => {
+							if self.verbose {
+								println!("\x1b[93mDeduplicated:\x1b[m {}", &src_path.display());
+							}
+							Ok(CopyStats::default())
+						}
+						Err(err) => {
+							println!("\x1b[91mFailed to hardlink file:\x1b[m {}", err);
+							Err(err)
+						}
+					};
+				}
+			}
 			match std::fs::copy(&src_path, &dest_path) {
 				Ok(bytes_copied) => {
-					self.bytes_copied += bytes_copied;
-					self.files_copied += 1;
+					preserve_file_times(&dest_path, src_meta);
 					println!("\x1b[92mChanged:\x1b[m {}", &src_path.display());
-               return Ok(());
+					Ok(CopyStats { bytes: bytes_copied, files: 1 })
 				}
 				Err(err) => {
 					println!("\x1b[91mFailed to copy file:\x1b[m {}", &err);
-               return Err(err);
+					Err(err)
 				}
 			}
 		} else {
 			match std::fs::hard_link(&prev_path, &dest_path) {
 				Ok(_) => {
+					if let Some(digest) = src_digest {
+						self.digests.lock().unwrap().entry(digest).or_insert_with(|| dest_path.clone());
+					}
 					if self.verbose {
 						println!("\x1b[93mLinked:\x1b[m {}", &src_path.display());
 					}
-               return Ok(());
+					Ok(CopyStats::default())
             }
 				Err(err) => {
 					println!("\x1b[91mFailed to hardlink file:\x1b[m {}", err);
-               return Err(err);
+					Err(err)
 				}
 			}
 		}
    }
-   fn dir(&mut self, dir: &std::fs::DirEntry, _: &std::fs::Metadata) -> std::io::Result<()> {
+   fn dir(&self, dir: &std::fs::DirEntry, _: &std::fs::Metadata) -> std::io::Result<()> {
       make_dir(&dir.path(), self.src_base_dir, self.dest_dir)
    }
+   fn special(&self, entry: &std::fs::DirEntry, _meta: &std::fs::Metadata, kind: SpecialKind) -> std::io::Result<()> {
+      handle_special(self.src_base_dir, self.dest_dir, entry, kind)
+   }
 }
 
-pub struct CopyFileHandler<'a>{
+pub struct RestoreFileHandler<'a>{
    pub src_base_dir: &'a Path,
    pub dest_dir: &'a Path,
-   pub bytes_copied: u64,
-   pub files_copied: u64,
+   pub force: bool,
 }
 
-impl<'a> FileHandler for CopyFileHandler<'a>{
-   fn file(&mut self, file: &std::fs::DirEntry, _meta: &std::fs::Metadata) -> std::io::Result<()> {
+impl<'a> FileHandler for RestoreFileHandler<'a>{
+   fn file(&self, file: &std::fs::DirEntry, meta: &std::fs::Metadata) -> std::io::Result<CopyStats> {
       let src_path = file.path();
       let src_file = src_path.strip_prefix(self.src_base_dir).unwrap(); // TODO don't panic here
-		let mut dest_path = PathBuf::from(self.dest_dir);
-		dest_path.push(&src_file);
+      let mut dest_path = PathBuf::from(self.dest_dir);
+      dest_path.push(src_file);
+      // Copy rather than hard-link so the restored tree is independent of the backup store.
+      if dest_path.exists() && !self.force {
+         // Warn and skip this one file (like a device node) rather than aborting the whole
+         // restore; a single pre-existing file shouldn't leave a half-materialized tree.
+         println!("\x1b[91mRefusing to overwrite:\x1b[m {} (pass --force to overwrite)", &dest_path.display());
+         return Ok(CopyStats::default());
+      }
       match std::fs::copy(&src_path, &dest_path) {
-			Ok(bytes_copied) => {
-				self.bytes_copied += bytes_copied;
-				self.files_copied += 1;
-				println!("\x1b[92mCopied:\x1b[m {}", &src_path.display());
-            return Ok(());
-			}
-			Err(err) => {
-				println!("\x1b[91mFailed to copy file:\x1b[m {}", &err);
-            return Err(err);
-			}
-		}
+         Ok(bytes_copied) => {
+            preserve_file_times(&dest_path, meta);
+            println!("\x1b[92mRestored:\x1b[m {}", &dest_path.display());
+            Ok(CopyStats { bytes: bytes_copied, files: 1 })
+         }
+         Err(err) => {
+            println!("\x1b[91mFailed to restore file:\x1b[m {}", &err);
+            Err(err)
+         }
+      }
    }
-   fn dir(&mut self, dir: &std::fs::DirEntry, _meta: &std::fs::Metadata) -> std::io::Result<()> {
+   fn dir(&self, dir: &std::fs::DirEntry, _meta: &std::fs::Metadata) -> std::io::Result<()> {
       make_dir(&dir.path(), self.src_base_dir, self.dest_dir)
    }
+   fn special(&self, entry: &std::fs::DirEntry, _meta: &std::fs::Metadata, kind: SpecialKind) -> std::io::Result<()> {
+      handle_special(self.src_base_dir, self.dest_dir, entry, kind)
+   }
+}
+
+// Shared handling for the copy/link/restore handlers: recreate preserved symlinks, and warn
+// and skip on device/FIFO/socket nodes rather than failing the whole run.
+fn handle_special(src_base_dir: &Path, dest_dir: &Path, entry: &DirEntry, kind: SpecialKind) -> std::io::Result<()> {
+   match kind {
+      SpecialKind::Symlink => preserve_symlink(src_base_dir, dest_dir, entry),
+      SpecialKind::Other => {
+         println!("\x1b[mSkipping special file (device/FIFO/socket): {}", entry.path().display());
+         Ok(())
+      }
+   }
+}
+
+fn preserve_symlink(src_base_dir: &Path, dest_dir: &Path, entry: &DirEntry) -> std::io::Result<()> {
+   let src_path = entry.path();
+   let src_file = src_path.strip_prefix(src_base_dir).unwrap(); // TODO don't panic here
+   let mut dest_path = PathBuf::from(dest_dir);
+   dest_path.push(src_file);
+   let target = std::fs::read_link(&src_path)?;
+   match create_symlink(&target, &dest_path) {
+      Ok(_) => {
+         println!("\x1b[93mSymlink:\x1b[m {} -> {}", &dest_path.display(), &target.display());
+         Ok(())
+      }
+      Err(err) => {
+         println!("\x1b[91mFailed to create symlink:\x1b[m {}", &err);
+         Err(err)
+      }
+   }
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+   std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+   // Windows distinguishes file and directory links; pick based on what the target resolves to,
+   // defaulting to a file link when it can't be determined (e.g. a dangling link).
+   if link.parent().map(|p| p.join(target)).as_deref().unwrap_or(target).is_dir() {
+      std::os::windows::fs::symlink_dir(target, link)
+   } else {
+      std::os::windows::fs::symlink_file(target, link)
+   }
 }
 
 fn make_dir(src_dir: &Path, src_base_dir: &Path, dest_dir: &Path) -> std::io::Result<()> {
    let src_dir = src_dir.strip_prefix(src_base_dir).unwrap(); // TODO don't panic here
    let mut dir_to_create = PathBuf::from(dest_dir);
-   dir_to_create.push(&src_dir);
+   dir_to_create.push(src_dir);
    if !dir_to_create.exists() {
       std::fs::create_dir_all(&dir_to_create)?;
    }
    Ok(())
 }
 
-fn diff_secs(t1: &SystemTime, t2: &SystemTime) -> Result<u64, SystemTimeError> {
-	let seconds1 = t1.duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
-	let seconds2 = t2.duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+// Record a file's metadata for the manifest, keyed by its path relative to the backup root so
+// the key survives the working directory being renamed into place.
+fn record_meta(collected: &Mutex<HashMap<PathBuf, FileMeta>>, dest_base_dir: &Path, dest_path: &Path, meta: FileMeta) {
+	let rel = dest_path.strip_prefix(dest_base_dir).unwrap_or(dest_path).to_path_buf();
+	collected.lock().unwrap().insert(rel, meta);
+}
 
-	// Return the absolute difference in seconds
-	if seconds1 > seconds2 {
-		Ok(seconds1 - seconds2)
-	} else {
-		Ok(seconds2 - seconds1)
+fn hash_file(path: &Path) -> std::io::Result<Digest> {
+	// Stream the file through the hasher so we never hold more than a buffer in memory.
+	let mut file = std::fs::File::open(path)?;
+	let mut hasher = blake3::Hasher::new();
+	std::io::copy(&mut file, &mut hasher)?;
+	Ok(*hasher.finalize().as_bytes())
+}
+
+fn preserve_file_times(dest: &Path, src_meta: &std::fs::Metadata) {
+	// std::fs::copy does not carry over the source timestamps on all platforms, which
+	// would make every file look "just modified" and break the mtime-based change
+	// detection on the next run. Stamp the destination with the source's times instead.
+	let atime = filetime::FileTime::from_last_access_time(src_meta);
+	let mtime = filetime::FileTime::from_last_modification_time(src_meta);
+	if let Err(err) = filetime::set_file_times(dest, atime, mtime) {
+		println!("\x1b[mFailed to preserve modification time for {}: {}", &dest.display(), &err);
 	}
 }
 
-pub fn handle_files_recursive(
-   base_path: &Path,
-   excluded: &HashSet<OsString>,
-   handler: &mut dyn FileHandler,
+// Writing the manifest lets the next run skip the full directory walk of this backup: we
+// store each entry's path (relative to the backup root, so it survives the directory being
+// renamed), length, modification time and optional hash, serialized and zstd-compressed.
+pub fn write_manifest(
+   backup_base_dir: &Path,
+   files: &HashMap<PathBuf, FileMeta>,
 ) -> std::io::Result<()> {
-   let mut dirs: Vec<PathBuf> = Vec::new();
-   dirs.push(PathBuf::from(base_path));
-
-   while let Some(path) = dirs.pop() {
-      for entry in std::fs::read_dir(path)? {
-         let entry: DirEntry = entry?;
-         let meta = entry.metadata()?;
-         if !excluded.contains(&entry.file_name()) {
-            if meta.is_dir() {
-               handler.dir(&entry, &meta)?;
-               dirs.push(entry.path());
-            } else if meta.is_file() {
-               handler.file(&entry, &meta)?;
+   let mut relative: HashMap<&Path, &FileMeta> = HashMap::with_capacity(files.len());
+   for (path, meta) in files.iter() {
+      let rel = path.strip_prefix(backup_base_dir).unwrap_or(path);
+      relative.insert(rel, meta);
+   }
+
+   let manifest_path = backup_base_dir.join(MANIFEST_NAME);
+   let file = std::fs::File::create(&manifest_path)?;
+   let mut encoder = zstd::stream::write::Encoder::new(file, 0)?;
+   serde_json::to_writer(&mut encoder, &relative).map_err(to_io_err)?;
+   encoder.finish()?;
+   Ok(())
+}
+
+// Read a previously written manifest, rebuilding the absolute paths under `backup_base_dir`
+// so the result matches what a `CollectorFileHandler` walk would have produced.
+pub fn read_manifest(backup_base_dir: &Path) -> std::io::Result<HashMap<PathBuf, FileMeta>> {
+   let manifest_path = backup_base_dir.join(MANIFEST_NAME);
+   let file = std::fs::File::open(&manifest_path)?;
+   let decoder = zstd::stream::read::Decoder::new(file)?;
+   let relative: HashMap<PathBuf, FileMeta> = serde_json::from_reader(decoder).map_err(to_io_err)?;
+
+   let mut files = HashMap::with_capacity(relative.len());
+   for (rel, meta) in relative {
+      files.insert(backup_base_dir.join(rel), meta);
+   }
+   Ok(files)
+}
+
+fn to_io_err(err: serde_json::Error) -> std::io::Error {
+   std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+}
+
+// Shared state for the parallel walk. Directories are handled inline as they are
+// discovered (so child directories exist before their files are dispatched), while files
+// are spawned onto the worker pool. Counters are atomics and the first error wins.
+struct WalkState<'a> {
+   excluded: &'a HashSet<OsString>,
+   handler: &'a dyn FileHandler,
+   symlinks: SymlinkMode,
+   bytes: AtomicU64,
+   files: AtomicU64,
+   entries_checked: AtomicU64,
+   err: Mutex<Option<std::io::Error>>,
+}
+
+impl<'a> WalkState<'a> {
+   fn record_err(&self, err: std::io::Error) {
+      let mut slot = self.err.lock().unwrap();
+      if slot.is_none() {
+         *slot = Some(err);
+      }
+   }
+
+   fn failed(&self) -> bool {
+      self.err.lock().unwrap().is_some()
+   }
+
+   // Count one handled entry and, every so often, print a progress line.
+   fn checked(&self) {
+      let checked = self.entries_checked.fetch_add(1, Ordering::Relaxed) + 1;
+      if checked.is_multiple_of(1000) {
+         let progress = ProgressData {
+            entries_checked: checked,
+            bytes_copied: self.bytes.load(Ordering::Relaxed),
+         };
+         println!(
+            "\x1b[mProgress: {} entries checked, {} bytes copied",
+            progress.entries_checked, progress.bytes_copied
+         );
+      }
+   }
+}
+
+fn walk<'a>(state: &'a WalkState<'a>, scope: &rayon::Scope<'a>, path: PathBuf) {
+   if state.failed() {
+      return;
+   }
+   let read_dir = match std::fs::read_dir(&path) {
+      Ok(rd) => rd,
+      Err(err) => return state.record_err(err),
+   };
+   for entry in read_dir {
+      let entry: DirEntry = match entry {
+         Ok(entry) => entry,
+         Err(err) => return state.record_err(err),
+      };
+      if state.excluded.contains(&entry.file_name()) {
+         continue;
+      }
+      // `file_type()` does not traverse symbolic links, so it's what we use to classify the
+      // entry; `std::fs::metadata` (which follows links) is only taken once we've decided to follow.
+      let file_type = match entry.file_type() {
+         Ok(ft) => ft,
+         Err(err) => return state.record_err(err),
+      };
+
+      if file_type.is_symlink() {
+         match state.symlinks {
+            SymlinkMode::Skip => {
+               state.checked();
+            }
+            SymlinkMode::Preserve => {
+               // Recreate the link itself and do not descend into symlinked directories.
+               let meta = match entry.path().symlink_metadata() {
+                  Ok(meta) => meta,
+                  Err(err) => return state.record_err(err),
+               };
+               if let Err(err) = state.handler.special(&entry, &meta, SpecialKind::Symlink) {
+                  return state.record_err(err);
+               }
+               state.checked();
             }
+            SymlinkMode::Follow => {
+               // Follow the link and treat the entry as whatever it points at.
+               let meta = match std::fs::metadata(entry.path()) {
+                  Ok(meta) => meta,
+                  Err(err) => return state.record_err(err),
+               };
+               handle_resolved(state, scope, entry, meta);
+            }
+         }
+      } else if file_type.is_dir() || file_type.is_file() {
+         // A regular file or directory: resolve its metadata and dispatch it. `handle_resolved`
+         // recurses into directories and hands files to the worker pool.
+         let meta = match entry.metadata() {
+            Ok(meta) => meta,
+            Err(err) => return state.record_err(err),
+         };
+         handle_resolved(state, scope, entry, meta);
+      } else {
+         // FIFO, socket or device node: warn and skip rather than hang or error.
+         let meta = match entry.path().symlink_metadata() {
+            Ok(meta) => meta,
+            Err(err) => return state.record_err(err),
+         };
+         if let Err(err) = state.handler.special(&entry, &meta, SpecialKind::Other) {
+            return state.record_err(err);
          }
+         state.checked();
       }
    }
-   Ok(())
+}
+
+// Dispatch an entry whose (possibly link-followed) metadata is known: recurse into
+// directories, hand files to the worker pool, and treat anything else as a special node.
+fn handle_resolved<'a>(state: &'a WalkState<'a>, scope: &rayon::Scope<'a>, entry: DirEntry, meta: std::fs::Metadata) {
+   if meta.is_dir() {
+      if let Err(err) = state.handler.dir(&entry, &meta) {
+         return state.record_err(err);
+      }
+      let child = entry.path();
+      scope.spawn(move |scope| walk(state, scope, child));
+      state.checked();
+   } else if meta.is_file() {
+      scope.spawn(move |_| {
+         if state.failed() {
+            return;
+         }
+         match state.handler.file(&entry, &meta) {
+            Ok(stats) => {
+               state.bytes.fetch_add(stats.bytes, Ordering::Relaxed);
+               state.files.fetch_add(stats.files, Ordering::Relaxed);
+            }
+            Err(err) => state.record_err(err),
+         }
+         state.checked();
+      });
+   } else {
+      if let Err(err) = state.handler.special(&entry, &meta, SpecialKind::Other) {
+         return state.record_err(err);
+      }
+      state.checked();
+   }
+}
+
+pub fn handle_files_recursive(
+   base_path: &Path,
+   excluded: &HashSet<OsString>,
+   handler: &dyn FileHandler,
+   jobs: usize,
+   symlinks: SymlinkMode,
+) -> std::io::Result<CopyStats> {
+   let state = WalkState {
+      excluded,
+      handler,
+      symlinks,
+      bytes: AtomicU64::new(0),
+      files: AtomicU64::new(0),
+      entries_checked: AtomicU64::new(0),
+      err: Mutex::new(None),
+   };
+
+   let pool = rayon::ThreadPoolBuilder::new()
+      .num_threads(jobs)
+      .build()
+      .map_err(std::io::Error::other)?;
+
+   pool.in_place_scope(|scope| walk(&state, scope, PathBuf::from(base_path)));
+
+   if let Some(err) = state.err.into_inner().unwrap() {
+      return Err(err);
+   }
+   Ok(CopyStats {
+      bytes: state.bytes.load(Ordering::Relaxed),
+      files: state.files.load(Ordering::Relaxed),
+   })
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::time::Duration;
+
+   #[test]
+   fn timestamp_round_trips_through_system_time() {
+      let time = SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+      let ts = Timestamp::from_system_time(time);
+      assert_eq!(ts.secs, 1_700_000_000);
+      assert_eq!(ts.nanos, 123_456_789);
+   }
+
+   #[test]
+   fn timestamp_before_epoch_carries_negative_seconds() {
+      let time = SystemTime::UNIX_EPOCH - Duration::new(5, 0);
+      let ts = Timestamp::from_system_time(time);
+      assert_eq!(ts.secs, -5);
+   }
+
+   #[test]
+   fn same_second_ignores_nanoseconds() {
+      let a = Timestamp { secs: 10, nanos: 1 };
+      let b = Timestamp { secs: 10, nanos: 999_999_999 };
+      let c = Timestamp { secs: 11, nanos: 0 };
+      assert!(a.same_second(&b));
+      assert!(!a.same_second(&c));
+   }
+
+   #[test]
+   fn manifest_round_trips_through_zstd_and_serde() {
+      // A unique scratch directory under the system temp dir (no rng available in tests).
+      let mut dir = std::env::temp_dir();
+      dir.push(format!("incbackup-manifest-test-{}", std::process::id()));
+      std::fs::create_dir_all(&dir).unwrap();
+
+      let mut files = HashMap::new();
+      files.insert(
+         dir.join("sub/file.txt"),
+         FileMeta { len: 42, modified: Timestamp { secs: 99, nanos: 7 }, hash: Some([1u8; 32]), ambiguous: true },
+      );
+
+      write_manifest(&dir, &files).unwrap();
+      let read = read_manifest(&dir).unwrap();
+
+      assert_eq!(read.len(), 1);
+      let meta = read.get(&dir.join("sub/file.txt")).expect("path relativized and rebuilt");
+      assert_eq!(meta.len, 42);
+      assert_eq!(meta.modified, Timestamp { secs: 99, nanos: 7 });
+      assert_eq!(meta.hash, Some([1u8; 32]));
+      assert!(meta.ambiguous);
+
+      std::fs::remove_dir_all(&dir).unwrap();
+   }
 }
\ No newline at end of file
@@ -7,19 +7,30 @@ use std::collections::{HashMap, HashSet};
 use std::ffi::OsString;
 use std::fs::OpenOptions;
 use std::io::Write;
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-struct CopyStats {
-	bytes: u64,
-	files: u64,
-}
+use files::CopyStats;
 
-// TODO should be able to exclude symlinks/hardlinks/junctions from the source directories
 #[derive(Parser, Debug)]
 #[clap(name = "incbackup")]
 #[clap(author = "Michael McBride")]
 #[clap(version, about, long_about = None)]
-struct Arguments {
+struct Cli {
+	#[clap(subcommand)]
+	command: Command,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+	#[clap(about = "Create an incremental backup of the source directories")]
+	Backup(BackupArgs),
+	#[clap(about = "Restore a snapshot from a backup to a target directory")]
+	Restore(RestoreArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct BackupArgs {
 	backup_path: String,
 	#[clap(short = 'd', long = "dir", help = "The source directories to be included in the backup")]
 	src_dirs: Vec<String>,
@@ -27,14 +38,50 @@ struct Arguments {
 	excluded_names: Option<Vec<String>>,
 	#[clap(short = 's', long = "stats", help = "If present, will append stats to this file as comma-separated values (date,total_bytes_copied,total_files_copied)")]
 	path_stats: Option<String>,
-	#[clap(short = 'm', long = "min-diff", default_value_t = 1, help = "If the file modification time differs by at least this many seconds, the file will be backed up")]
+	#[clap(short = 'm', long = "min-diff", default_value_t = 0, help = "Tolerance in whole seconds when comparing modification times. The default of 0 compares at full precision; a larger value treats times within that many seconds as unchanged")]
 	min_diff_secs: u64,
+	#[clap(long = "hash", help = "Compare file contents by hash instead of trusting length/modification time, and deduplicate identical files within the backup via hard links")]
+	hash: bool,
+	#[clap(short = 'j', long = "jobs", help = "Number of worker threads to use for walking and copying (defaults to the number of available CPUs)")]
+	jobs: Option<usize>,
+	#[clap(long = "symlinks", value_enum, default_value_t = files::SymlinkMode::Follow, help = "How to handle symbolic links in the source: follow the target, preserve the link, or skip it")]
+	symlinks: files::SymlinkMode,
 	#[clap(short = 'v', long = "verbose", help = "If present, will output information for all links created")]
 	verbose: bool,
 }
 
+#[derive(clap::Args, Debug)]
+struct RestoreArgs {
+	#[clap(help = "The backup store to restore from")]
+	backup_path: String,
+	#[clap(help = "The snapshot to restore, named as it appears in the backup store (YYYY-MM-DD HH-MM-SS)")]
+	snapshot_date: String,
+	#[clap(help = "The directory the snapshot will be restored into")]
+	target: String,
+	#[clap(short = 'd', long = "dir", help = "Restore only this source subtree (named as it appears in the snapshot) instead of the whole snapshot")]
+	src_dir: Option<String>,
+	#[clap(short = 'j', long = "jobs", help = "Number of worker threads to use (defaults to the number of available CPUs)")]
+	jobs: Option<usize>,
+	#[clap(long = "force", help = "Overwrite files that already exist in the target directory")]
+	force: bool,
+}
+
 fn main() {
-	let args = Arguments::parse();
+	match Cli::parse().command {
+		Command::Backup(args) => backup(args),
+		Command::Restore(args) => restore(args),
+	}
+}
+
+fn jobs_or_default(jobs: Option<usize>) -> usize {
+	jobs.unwrap_or_else(|| {
+		std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+	})
+}
+
+fn backup(args: BackupArgs) {
+	let jobs = jobs_or_default(args.jobs);
+	let backup_started = files::Timestamp::from_system_time(std::time::SystemTime::now());
 	let backup_path = args.backup_path;
 	let src_dirs = args.src_dirs;
 	let mut excluded_names = HashSet::new();
@@ -58,16 +105,13 @@ fn main() {
 	}
 	drop(backup_path_buf);
 
-	let date_dirs;
-	match get_dirs(&backup_path) {
-		Ok(ok) => {
-			date_dirs = ok;
-		}
+	let date_dirs = match get_dirs(&backup_path) {
+		Ok(ok) => ok,
 		Err(err) => {
 			println!("Error reading directory ({}): {}", &backup_path, &err);
 			return;
 		}
-	}
+	};
 
 	// Now we want to get the latest backup date/time
 	let latest_date = date_dirs.iter().fold(
@@ -110,29 +154,47 @@ fn main() {
 		}
 	}
 	let dest_base_dir = &backup_base_dir_working;
-	let mut prev_files_collector = files::CollectorFileHandler{files: HashMap::new()};
 
-	if let Some(prev_base_dir) = maybe_prev_base_dir {
-		match files::handle_files_recursive(&prev_base_dir, &excluded_names, &mut prev_files_collector) {
-			Ok(_) => {
-				println!("Previous backup directory: {}", &prev_base_dir.display());
+	let prev_files = if let Some(prev_base_dir) = maybe_prev_base_dir {
+		// Prefer the compressed manifest the previous run left behind; only walk the whole
+		// backup directory when there isn't one or it can't be read.
+		match files::read_manifest(prev_base_dir) {
+			Ok(files) => {
+				println!("Previous backup directory (from manifest): {}", &prev_base_dir.display());
+				files
 			}
-			Err(err) => {
-				println!(
-					"Error reading backup directory {}: {}",
-					&prev_base_dir.display(),
-					&err
-				);
-				return;
+			Err(_) => {
+				let collector = files::CollectorFileHandler{files: Mutex::new(HashMap::new()), backup_started: None, hash: args.hash};
+				match files::handle_files_recursive(prev_base_dir, &excluded_names, &collector, jobs, files::SymlinkMode::Preserve) {
+					Ok(_) => {
+						println!("Previous backup directory: {}", &prev_base_dir.display());
+					}
+					Err(err) => {
+						println!(
+							"Error reading backup directory {}: {}",
+							&prev_base_dir.display(),
+							&err
+						);
+						return;
+					}
+				}
+				collector.files.into_inner().unwrap()
 			}
 		}
 	} else {
 		println!("First backup, everything will be copied");
-	}
-	let prev_files = prev_files_collector.files;
+		HashMap::new()
+	};
 
 	let mut total_bytes_copied = 0u64;
 	let mut total_files_copied = 0u64;
+	// Metadata of every file written this run, accumulated by the handlers across all source
+	// directories so the manifest is serialized from it rather than by re-walking the finished
+	// backup.
+	let collected: Mutex<HashMap<PathBuf, files::FileMeta>> = Mutex::new(HashMap::new());
+	// Digest -> first destination written with that content. Shared across all source directories
+	// so content-addressed deduplication spans the whole backup, including the first one.
+	let digests: Mutex<HashMap<files::Digest, PathBuf>> = Mutex::new(HashMap::new());
 	for src_base_dir in src_dirs {
 		let src_base_dir = PathBuf::from(src_base_dir);
 
@@ -171,39 +233,26 @@ fn main() {
 			&dest_dir.display()
 		);
 		
-		let result = 
-		match maybe_prev_dir {
-			Some(prev_dir) => {
-				let mut handler = files::LinkOrCopyFileHandler
-				{
-					prev_files: &prev_files,
-					src_base_dir: &src_base_dir,
-					dest_dir: &dest_dir,
-					prev_dir: &prev_dir,
-					min_diff_secs: args.min_diff_secs,
-					bytes_copied: 0,
-					files_copied: 0,
-					verbose: args.verbose,
-				};
-				match files::handle_files_recursive(&src_base_dir, &excluded_names, &mut handler) {
-					Ok(_) => {Ok(CopyStats{bytes: handler.bytes_copied, files: handler.files_copied})}
-					Err(err) => {Err(err)}
-				}
-			}
-			None => {
-				let mut handler = files::CopyFileHandler
-				{
-					src_base_dir: &src_base_dir,
-					dest_dir: &dest_dir,
-					bytes_copied: 0,
-					files_copied: 0,
-				};
-				match files::handle_files_recursive(&src_base_dir, &excluded_names, &mut handler) {
-					Ok(_) => {Ok(CopyStats{bytes: handler.bytes_copied, files: handler.files_copied})}
-					Err(err) => {Err(err)}
-				}
-			}
+		// On the first backup there is no previous snapshot, so `prev_files` is empty and every
+		// file takes the copy path; routing through the same handler still applies content-addressed
+		// deduplication to that initial full backup. `prev_dir` is unused when `prev_files` is empty.
+		let prev_dir = maybe_prev_dir.unwrap_or_else(|| dest_dir.clone());
+		let handler = files::LinkOrCopyFileHandler
+		{
+			prev_files: &prev_files,
+			src_base_dir: &src_base_dir,
+			dest_dir: &dest_dir,
+			dest_base_dir,
+			prev_dir: &prev_dir,
+			min_diff_secs: args.min_diff_secs,
+			backup_started,
+			hash: args.hash,
+			digests: &digests,
+			collected: &collected,
+			verbose: args.verbose,
 		};
+		let result: std::io::Result<CopyStats> =
+			files::handle_files_recursive(&src_base_dir, &excluded_names, &handler, jobs, args.symlinks);
 		match result {
 			Ok(stats) => {
 				total_bytes_copied += stats.bytes;
@@ -217,7 +266,19 @@ fn main() {
 	}
 
 	match std::fs::rename(&backup_base_dir_working, &backup_base_dir) {
-		Ok(_) => {}
+		Ok(_) => {
+			// Record a manifest of the finished backup so the next run can skip re-walking it.
+			// The metadata was collected during the copy pass above (keyed relative to the backup
+			// root), so there is no second walk of the tree here.
+			let manifest_files = collected.into_inner().unwrap();
+			if let Err(err) = files::write_manifest(&backup_base_dir, &manifest_files) {
+				println!(
+					"Failed to write manifest for backup {} because {}",
+					backup_base_dir.display(),
+					err
+				);
+			}
+		}
 		Err(err) => {
 			println!(
 				"Failed to remove -inprogress from directory {} because {}",
@@ -229,31 +290,26 @@ fn main() {
 
 	println!("\x1b[mTotal bytes copied: {}", &total_bytes_copied);
 	println!("Total files copied: {}", &total_files_copied);
-	match args.path_stats {
-		Some(path) => {
+	if let Some(path) = args.path_stats {
 			let path = PathBuf::from(path);
 			if let Some(parent) = path.parent() {
-				match std::fs::create_dir_all(&parent) {
-					Ok(_) => {}
-					Err(err) => {
-						println!(
-							"Failed to create directory for stats file {} because {}",
-							&parent.display(),
-							err
-						);
-					}
+				if let Err(err) = std::fs::create_dir_all(parent) {
+					println!(
+						"Failed to create directory for stats file {} because {}",
+						&parent.display(),
+						err
+					);
 				}
 			}
 			match OpenOptions::new()
 				.create(true)
-				.write(true)
 				.append(true)
 				.open(&path)
 			{
 				Ok(mut file) => {
-					match write!(
+					match writeln!(
 						file,
-						"{},{},{}\n",
+						"{},{},{}",
 						&orig_name, &total_bytes_copied, &total_files_copied
 					) {
 						Ok(_) => {}
@@ -274,8 +330,75 @@ fn main() {
 					);
 				}
 			}
+	}
+}
+
+fn restore(args: RestoreArgs) {
+	let jobs = jobs_or_default(args.jobs);
+
+	// Resolve the requested snapshot via the same dated-directory parser the backup uses.
+	let date = match chrono::NaiveDateTime::parse_from_str(&args.snapshot_date, "%Y-%m-%d %H-%M-%S") {
+		Ok(date) => date,
+		Err(err) => {
+			println!(
+				"Invalid snapshot date \"{}\": {} (expected YYYY-MM-DD HH-MM-SS)",
+				&args.snapshot_date, err
+			);
+			return;
+		}
+	};
+
+	let date_dirs = match get_dirs(&args.backup_path) {
+		Ok(ok) => ok,
+		Err(err) => {
+			println!("Error reading directory ({}): {}", &args.backup_path, &err);
+			return;
+		}
+	};
+
+	let snapshot_dir = match date_dirs.get(&date) {
+		Some(dir) => dir.clone(),
+		None => {
+			println!("No snapshot named \"{}\" found in {}", &args.snapshot_date, &args.backup_path);
+			return;
+		}
+	};
+
+	// Optionally narrow the restore to a single source subtree within the snapshot.
+	let src_base_dir = match &args.src_dir {
+		Some(sub) => snapshot_dir.join(sub),
+		None => snapshot_dir.clone(),
+	};
+	if !src_base_dir.exists() {
+		println!("Nothing to restore: {} does not exist in the snapshot", &src_base_dir.display());
+		return;
+	}
+
+	let target = PathBuf::from(&args.target);
+	if let Err(err) = std::fs::create_dir_all(&target) {
+		println!("Failed to create target directory {} because {}", &target.display(), err);
+		return;
+	}
+
+	// The manifest is internal bookkeeping, not part of the backed-up tree, so never restore it.
+	let mut excluded_names = HashSet::new();
+	excluded_names.insert(OsString::from(files::MANIFEST_NAME));
+
+	let handler = files::RestoreFileHandler {
+		src_base_dir: &src_base_dir,
+		dest_dir: &target,
+		force: args.force,
+	};
+
+	println!("Restoring \"{}\" to \"{}\"", &src_base_dir.display(), &target.display());
+	match files::handle_files_recursive(&src_base_dir, &excluded_names, &handler, jobs, files::SymlinkMode::Preserve) {
+		Ok(stats) => {
+			println!("\x1b[mTotal bytes restored: {}", stats.bytes);
+			println!("Total files restored: {}", stats.files);
+		}
+		Err(err) => {
+			println!("Error occurred while restoring: {}", err);
 		}
-		None => {}
 	}
 }
 
@@ -285,11 +408,8 @@ fn get_dirs(path: &str) -> std::io::Result<HashMap<NaiveDateTime, PathBuf>> {
 		let entry = entry?;
 
 		if let Some(name) = entry.file_name().to_str() {
-			match chrono::NaiveDateTime::parse_from_str(name, "%Y-%m-%d %H-%M-%S") {
-				Ok(date) => {
-					dates.insert(date, entry.path());
-				}
-				Err(_) => {}
+			if let Ok(date) = chrono::NaiveDateTime::parse_from_str(name, "%Y-%m-%d %H-%M-%S") {
+				dates.insert(date, entry.path());
 			}
 		}
 	}
@@ -297,7 +417,7 @@ fn get_dirs(path: &str) -> std::io::Result<HashMap<NaiveDateTime, PathBuf>> {
 	Ok(dates)
 }
 
-fn dest_dir_from_src_leaf_dir(src: &PathBuf, dest: &PathBuf, buf: &mut PathBuf) -> Option<()> {
+fn dest_dir_from_src_leaf_dir(src: &Path, dest: &Path, buf: &mut PathBuf) -> Option<()> {
 	buf.push(dest);
 	if let Some(src_parent) = src.parent() {
 		// Failing to strip a prefix that is a parent should never fail, but you never know...
@@ -305,5 +425,5 @@ fn dest_dir_from_src_leaf_dir(src: &PathBuf, dest: &PathBuf, buf: &mut PathBuf)
 		buf.push(sub_dir);
 		return Some(());
 	}
-	return None;
+	None
 }
\ No newline at end of file